@@ -5,21 +5,51 @@ mod writer;
 
 pub use self::{context::Context, filter::Filter, reader::Reader, writer::Writer};
 
-use std::{collections::HashSet, convert::TryFrom, io};
+use std::{collections::HashSet, convert::TryFrom, io, thread};
 
 use interval_tree::IntervalTree;
 use noodles_bam as bam;
 use noodles_gff as gff;
-use noodles_sam::{self as sam, header::ReferenceSequences};
+use noodles_sam::{
+    self as sam,
+    header::ReferenceSequences,
+    record::data::field::{Tag, Value},
+};
 
 use crate::{CigarToIntervals, Entry, Features, PairPosition, RecordPairs, StrandSpecification};
 
+/// The rule used to resolve the set of features overlapping a read (or read
+/// pair) into a single assignment.
+///
+/// This mirrors the `--mode` modes of htseq-count: `Union` is permissive
+/// (anything touched counts), while the two intersection modes require
+/// agreement across every aligned position before a gene is credited.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum OverlapMode {
+    /// Assign the union of features overlapping any aligned position.
+    Union,
+    /// Assign the intersection of features overlapping every aligned
+    /// position, including positions that overlap nothing.
+    IntersectionStrict,
+    /// Assign the intersection of features overlapping every aligned
+    /// position that overlaps at least one feature.
+    IntersectionNonempty,
+}
+
+impl Default for OverlapMode {
+    fn default() -> OverlapMode {
+        OverlapMode::Union
+    }
+}
+
 pub fn count_single_end_records<I>(
     records: I,
     features: &Features,
     references: &ReferenceSequences,
     filter: &Filter,
     strand_specification: StrandSpecification,
+    overlap_mode: OverlapMode,
+    fractional: bool,
 ) -> io::Result<Context>
 where
     I: Iterator<Item = io::Result<bam::Record>>,
@@ -35,6 +65,8 @@ where
             references,
             filter,
             strand_specification,
+            overlap_mode,
+            fractional,
             &record,
         )?;
     }
@@ -48,6 +80,8 @@ pub fn count_single_end_record(
     reference_sequences: &ReferenceSequences,
     filter: &Filter,
     strand_specification: StrandSpecification,
+    overlap_mode: OverlapMode,
+    fractional: bool,
     record: &bam::Record,
 ) -> io::Result<()> {
     if filter.filter(ctx, record)? {
@@ -64,20 +98,23 @@ pub fn count_single_end_record(
     };
 
     let intervals = CigarToIntervals::new(&cigar, start, flags, reverse);
+    let weight = if fractional { record_weight(record) } else { 1.0 };
 
     let tree = match get_tree(
         ctx,
         features,
         reference_sequences,
         record.reference_sequence_id(),
+        weight,
     )? {
         Some(t) => t,
         None => return Ok(()),
     };
 
-    let set = find(tree, intervals, strand_specification);
+    let sets = find(tree, intervals, strand_specification);
+    let set = resolve_overlaps(sets, overlap_mode);
 
-    update_intersections(ctx, set);
+    update_intersections(ctx, set, weight);
 
     Ok(())
 }
@@ -88,6 +125,37 @@ pub fn count_paired_end_records<I>(
     reference_sequences: &ReferenceSequences,
     filter: &Filter,
     strand_specification: StrandSpecification,
+    overlap_mode: OverlapMode,
+    fractional: bool,
+) -> io::Result<(Context, RecordPairs<I>)>
+where
+    I: Iterator<Item = io::Result<bam::Record>>,
+{
+    count_paired_end_records_impl(
+        records,
+        features,
+        reference_sequences,
+        filter,
+        strand_specification,
+        overlap_mode,
+        fractional,
+        true,
+    )
+}
+
+/// Shared by [`count_paired_end_records`] and the per-region pass of
+/// [`count_paired_end_records_parallel`], which passes `warn_singletons =
+/// false` since its leftovers still get a chance to be reunited in
+/// [`reconcile_cross_region_singletons`].
+fn count_paired_end_records_impl<I>(
+    records: I,
+    features: &Features,
+    reference_sequences: &ReferenceSequences,
+    filter: &Filter,
+    strand_specification: StrandSpecification,
+    overlap_mode: OverlapMode,
+    fractional: bool,
+    warn_singletons: bool,
 ) -> io::Result<(Context, RecordPairs<I>)>
 where
     I: Iterator<Item = io::Result<bam::Record>>,
@@ -97,6 +165,10 @@ where
     let primary_only = !filter.with_secondary_records() && !filter.with_supplementary_records();
     let mut pairs = RecordPairs::new(records, primary_only);
 
+    if !warn_singletons {
+        pairs.silence_singleton_warning();
+    }
+
     for pair in &mut pairs {
         let (r1, r2) = pair?;
 
@@ -104,58 +176,90 @@ where
             continue;
         }
 
-        let cigar = r1.cigar();
-        let start = (r1.position() + 1) as u64;
-        let f1 = r1.flags();
-
-        let reverse = match strand_specification {
-            StrandSpecification::Reverse => true,
-            _ => false,
-        };
-
-        let intervals = CigarToIntervals::new(&cigar, start, f1, reverse);
-
-        let tree = match get_tree(
+        count_paired_end_record(
             &mut ctx,
             features,
             reference_sequences,
-            r1.reference_sequence_id(),
-        )? {
-            Some(t) => t,
-            None => continue,
-        };
+            strand_specification,
+            overlap_mode,
+            fractional,
+            &r1,
+            &r2,
+        )?;
+    }
 
-        let mut set = find(tree, intervals, strand_specification);
+    Ok((ctx, pairs))
+}
 
-        let cigar = r2.cigar();
-        let start = (r2.position() + 1) as u64;
-        let f2 = r2.flags();
+/// Resolves and counts a single, already-paired (`r1`, `r2`) mate pair
+/// against `ctx`. Shared by [`count_paired_end_records`] and
+/// [`reconcile_cross_region_singletons`].
+fn count_paired_end_record(
+    ctx: &mut Context,
+    features: &Features,
+    reference_sequences: &ReferenceSequences,
+    strand_specification: StrandSpecification,
+    overlap_mode: OverlapMode,
+    fractional: bool,
+    r1: &bam::Record,
+    r2: &bam::Record,
+) -> io::Result<()> {
+    let cigar = r1.cigar();
+    let start = (r1.position() + 1) as u64;
+    let f1 = r1.flags();
 
-        let reverse = match strand_specification {
-            StrandSpecification::Reverse => false,
-            _ => true,
-        };
+    let reverse = match strand_specification {
+        StrandSpecification::Reverse => true,
+        _ => false,
+    };
 
-        let intervals = CigarToIntervals::new(&cigar, start, f2, reverse);
+    let intervals = CigarToIntervals::new(&cigar, start, f1, reverse);
+    let weight = if fractional { record_weight(r1) } else { 1.0 };
 
-        let tree = match get_tree(
-            &mut ctx,
-            features,
-            reference_sequences,
-            r2.reference_sequence_id(),
-        )? {
-            Some(t) => t,
-            None => continue,
-        };
+    let tree = match get_tree(
+        ctx,
+        features,
+        reference_sequences,
+        r1.reference_sequence_id(),
+        weight,
+    )? {
+        Some(t) => t,
+        None => return Ok(()),
+    };
 
-        let set2 = find(tree, intervals, strand_specification);
+    let mut sets = find(tree, intervals, strand_specification);
 
-        set.extend(set2.into_iter());
+    let cigar = r2.cigar();
+    let start = (r2.position() + 1) as u64;
+    let f2 = r2.flags();
 
-        update_intersections(&mut ctx, set);
-    }
+    let reverse = match strand_specification {
+        StrandSpecification::Reverse => false,
+        _ => true,
+    };
 
-    Ok((ctx, pairs))
+    let intervals = CigarToIntervals::new(&cigar, start, f2, reverse);
+
+    let tree = match get_tree(
+        ctx,
+        features,
+        reference_sequences,
+        r2.reference_sequence_id(),
+        weight,
+    )? {
+        Some(t) => t,
+        None => return Ok(()),
+    };
+
+    let sets2 = find(tree, intervals, strand_specification);
+
+    sets.extend(sets2);
+
+    let set = resolve_overlaps(sets, overlap_mode);
+
+    update_intersections(ctx, set, weight);
+
+    Ok(())
 }
 
 pub fn count_paired_end_record_singletons<I>(
@@ -164,6 +268,8 @@ pub fn count_paired_end_record_singletons<I>(
     reference_sequences: &ReferenceSequences,
     filter: &Filter,
     strand_specification: StrandSpecification,
+    overlap_mode: OverlapMode,
+    fractional: bool,
 ) -> io::Result<Context>
 where
     I: Iterator<Item = io::Result<bam::Record>>,
@@ -198,33 +304,289 @@ where
 
         let flags = record.flags();
         let intervals = CigarToIntervals::new(&cigar, start, flags, reverse);
+        let weight = if fractional { record_weight(&record) } else { 1.0 };
 
         let tree = match get_tree(
             &mut ctx,
             features,
             reference_sequences,
             record.reference_sequence_id(),
+            weight,
         )? {
             Some(t) => t,
             None => continue,
         };
 
-        let set = find(tree, intervals, strand_specification);
+        let sets = find(tree, intervals, strand_specification);
+        let set = resolve_overlaps(sets, overlap_mode);
 
-        update_intersections(&mut ctx, set);
+        update_intersections(&mut ctx, set, weight);
     }
 
     Ok(ctx)
 }
 
-fn find(
+/// Returns the fractional weight a record should contribute to its assigned
+/// feature(s), i.e. `1 / NH` where `NH` is the number of locations the read
+/// aligns to. Falls back to a weight of `1.0` when the `NH` auxiliary field
+/// is absent.
+fn record_weight(record: &bam::Record) -> f64 {
+    weight_from_nh(record.data().get(Tag::AlignmentHitCount).map(|field| field.value()))
+}
+
+/// Converts a raw `NH` tag value into a weight, defaulting to `1.0` when the
+/// value is missing, non-numeric, or non-positive. Split out from
+/// `record_weight` so the NH-to-weight arithmetic can be exercised without a
+/// real `bam::Record`.
+fn weight_from_nh(value: Option<&Value>) -> f64 {
+    let nh = match value {
+        Some(Value::Int8(n)) => *n as f64,
+        Some(Value::UInt8(n)) => *n as f64,
+        Some(Value::Int16(n)) => *n as f64,
+        Some(Value::UInt16(n)) => *n as f64,
+        Some(Value::Int32(n)) => *n as f64,
+        Some(Value::UInt32(n)) => *n as f64,
+        _ => 1.0,
+    };
+
+    if nh > 0.0 {
+        1.0 / nh
+    } else {
+        1.0
+    }
+}
+
+/// Counts single-end records in parallel: `regions` are distributed
+/// round-robin across `thread_count` workers, and the per-worker `Context`s
+/// are combined with [`merge_context`].
+pub fn count_single_end_records_parallel<T>(
+    regions: Vec<T>,
+    features: &Features,
+    reference_sequences: &ReferenceSequences,
+    filter: &Filter,
+    strand_specification: StrandSpecification,
+    overlap_mode: OverlapMode,
+    fractional: bool,
+    thread_count: usize,
+) -> io::Result<Context>
+where
+    T: IntoIterator<Item = io::Result<bam::Record>> + Send,
+{
+    let bucket_count = thread_count.max(1).min(regions.len().max(1));
+    let buckets = partition_round_robin(regions, bucket_count);
+
+    let worker_results: Vec<io::Result<Context>> = thread::scope(|scope| {
+        let handles: Vec<_> = buckets
+            .into_iter()
+            .map(|bucket| {
+                scope.spawn(|| {
+                    let mut ctx = Context::default();
+
+                    for region in bucket {
+                        let region_ctx = count_single_end_records(
+                            region.into_iter(),
+                            features,
+                            reference_sequences,
+                            filter,
+                            strand_specification,
+                            overlap_mode,
+                            fractional,
+                        )?;
+
+                        merge_context(&mut ctx, region_ctx);
+                    }
+
+                    Ok(ctx)
+                })
+            })
+            .collect();
+
+        handles
+            .into_iter()
+            .map(|handle| handle.join().expect("counting thread panicked"))
+            .collect()
+    });
+
+    let mut ctx = Context::default();
+
+    for result in worker_results {
+        merge_context(&mut ctx, result?);
+    }
+
+    Ok(ctx)
+}
+
+/// Counts paired-end records in parallel, like
+/// [`count_single_end_records_parallel`], but additionally reconciles mates
+/// split across two workers via [`reconcile_cross_region_singletons`].
+pub fn count_paired_end_records_parallel<T>(
+    regions: Vec<T>,
+    features: &Features,
+    reference_sequences: &ReferenceSequences,
+    filter: &Filter,
+    strand_specification: StrandSpecification,
+    overlap_mode: OverlapMode,
+    fractional: bool,
+    thread_count: usize,
+) -> io::Result<Context>
+where
+    T: IntoIterator<Item = io::Result<bam::Record>> + Send,
+{
+    let bucket_count = thread_count.max(1).min(regions.len().max(1));
+    let buckets = partition_round_robin(regions, bucket_count);
+
+    let worker_results: Vec<io::Result<(Context, Vec<bam::Record>)>> = thread::scope(|scope| {
+        let handles: Vec<_> = buckets
+            .into_iter()
+            .map(|bucket| {
+                scope.spawn(|| {
+                    let mut ctx = Context::default();
+                    let mut singletons = Vec::new();
+
+                    for region in bucket {
+                        let (region_ctx, mut pairs) = count_paired_end_records_impl(
+                            region.into_iter(),
+                            features,
+                            reference_sequences,
+                            filter,
+                            strand_specification,
+                            overlap_mode,
+                            fractional,
+                            false,
+                        )?;
+
+                        merge_context(&mut ctx, region_ctx);
+                        singletons.extend(pairs.singletons()?);
+                    }
+
+                    Ok((ctx, singletons))
+                })
+            })
+            .collect();
+
+        handles
+            .into_iter()
+            .map(|handle| handle.join().expect("counting thread panicked"))
+            .collect()
+    });
+
+    let mut ctx = Context::default();
+    let mut cross_region_singletons = Vec::new();
+
+    for result in worker_results {
+        let (region_ctx, singletons) = result?;
+        merge_context(&mut ctx, region_ctx);
+        cross_region_singletons.extend(singletons);
+    }
+
+    if !cross_region_singletons.is_empty() {
+        reconcile_cross_region_singletons(
+            &mut ctx,
+            cross_region_singletons,
+            features,
+            reference_sequences,
+            filter,
+            strand_specification,
+            overlap_mode,
+            fractional,
+        )?;
+    }
+
+    Ok(ctx)
+}
+
+/// Re-matches leftover records from [`count_paired_end_records_parallel`]'s
+/// workers via [`RecordPairs`]; records still unmatched afterwards are
+/// genuine singletons, counted via [`count_paired_end_record_singletons`].
+fn reconcile_cross_region_singletons(
+    ctx: &mut Context,
+    records: Vec<bam::Record>,
+    features: &Features,
+    reference_sequences: &ReferenceSequences,
+    filter: &Filter,
+    strand_specification: StrandSpecification,
+    overlap_mode: OverlapMode,
+    fractional: bool,
+) -> io::Result<()> {
+    let primary_only = !filter.with_secondary_records() && !filter.with_supplementary_records();
+    let mut pairs = RecordPairs::new(records.into_iter().map(Ok), primary_only);
+
+    for pair in &mut pairs {
+        let (r1, r2) = pair?;
+
+        if filter.filter_pair(ctx, &r1, &r2)? {
+            continue;
+        }
+
+        count_paired_end_record(
+            ctx,
+            features,
+            reference_sequences,
+            strand_specification,
+            overlap_mode,
+            fractional,
+            &r1,
+            &r2,
+        )?;
+    }
+
+    let singletons: Vec<_> = pairs.singletons()?.collect();
+
+    if !singletons.is_empty() {
+        let singleton_ctx = count_paired_end_record_singletons(
+            singletons.into_iter().map(Ok),
+            features,
+            reference_sequences,
+            filter,
+            strand_specification,
+            overlap_mode,
+            fractional,
+        )?;
+
+        merge_context(ctx, singleton_ctx);
+    }
+
+    Ok(())
+}
+
+/// Distributes `items` round-robin across `bucket_count` buckets, preserving
+/// each item's relative order within its bucket.
+fn partition_round_robin<T>(items: Vec<T>, bucket_count: usize) -> Vec<Vec<T>> {
+    let mut buckets: Vec<Vec<T>> = (0..bucket_count).map(|_| Vec::new()).collect();
+
+    for (i, item) in items.into_iter().enumerate() {
+        buckets[i % bucket_count].push(item);
+    }
+
+    buckets
+}
+
+/// Merges `other` into `ctx`, the associative operation the parallel
+/// counting drivers use to combine per-region `Context`s. Covers `counts`,
+/// `no_feature`, and `ambiguous` — every scalar/map field this module
+/// accumulates into `Context`; extend this if `Context` gains more.
+fn merge_context(ctx: &mut Context, other: Context) {
+    for (name, count) in other.counts {
+        let entry = ctx.counts.entry(name).or_insert(0.0);
+        *entry += count;
+    }
+
+    ctx.no_feature += other.no_feature;
+    ctx.ambiguous += other.ambiguous;
+}
+
+/// Finds the set of feature names overlapping each aligned position (CIGAR-derived
+/// interval) of a record, one set per position, for later resolution by [`resolve_overlaps`].
+pub(crate) fn find(
     tree: &IntervalTree<u64, Entry>,
     intervals: CigarToIntervals,
     strand_specification: StrandSpecification,
-) -> HashSet<String> {
-    let mut set = HashSet::new();
+) -> Vec<HashSet<String>> {
+    let mut sets = Vec::new();
 
     for (interval, is_reverse) in intervals {
+        let mut set = HashSet::new();
+
         for entry in tree.find(interval.clone()) {
             let gene_name = &entry.get().0;
             let strand = &entry.get().1;
@@ -242,9 +604,41 @@ fn find(
                 }
             }
         }
+
+        sets.push(set);
     }
 
-    set
+    sets
+}
+
+/// Combines the per-position overlap sets produced by [`find`] into a single
+/// set of feature names, according to the given [`OverlapMode`].
+pub(crate) fn resolve_overlaps(
+    sets: Vec<HashSet<String>>,
+    overlap_mode: OverlapMode,
+) -> HashSet<String> {
+    match overlap_mode {
+        OverlapMode::Union => sets.into_iter().fold(HashSet::new(), |mut acc, set| {
+            acc.extend(set);
+            acc
+        }),
+        OverlapMode::IntersectionStrict => intersect_all(sets.into_iter()),
+        OverlapMode::IntersectionNonempty => {
+            intersect_all(sets.into_iter().filter(|set| !set.is_empty()))
+        }
+    }
+}
+
+fn intersect_all<I>(mut sets: I) -> HashSet<String>
+where
+    I: Iterator<Item = HashSet<String>>,
+{
+    let first = match sets.next() {
+        Some(set) => set,
+        None => return HashSet::new(),
+    };
+
+    sets.fold(first, |acc, set| acc.intersection(&set).cloned().collect())
 }
 
 fn get_reference<'a>(
@@ -273,16 +667,16 @@ fn get_reference<'a>(
         })
 }
 
-fn update_intersections(ctx: &mut Context, intersections: HashSet<String>) {
+fn update_intersections(ctx: &mut Context, intersections: HashSet<String>, weight: f64) {
     if intersections.is_empty() {
-        ctx.no_feature += 1;
+        ctx.no_feature += weight;
     } else if intersections.len() == 1 {
         for name in intersections {
-            let count = ctx.counts.entry(name).or_insert(0);
-            *count += 1;
+            let count = ctx.counts.entry(name).or_insert(0.0);
+            *count += weight;
         }
     } else if intersections.len() > 1 {
-        ctx.ambiguous += 1;
+        ctx.ambiguous += weight;
     }
 }
 
@@ -291,6 +685,7 @@ pub fn get_tree<'t>(
     features: &'t Features,
     reference_sequences: &ReferenceSequences,
     ref_id: i32,
+    weight: f64,
 ) -> io::Result<Option<&'t IntervalTree<u64, Entry>>> {
     let reference = get_reference(reference_sequences, ref_id)?;
     let name = reference.name();
@@ -298,7 +693,7 @@ pub fn get_tree<'t>(
     match features.get(name) {
         Some(t) => Ok(Some(t)),
         None => {
-            ctx.no_feature += 1;
+            ctx.no_feature += weight;
             Ok(None)
         }
     }
@@ -341,4 +736,83 @@ mod tests {
         let reference = get_reference(&reference_sequences, 5);
         assert!(reference.is_err());
     }
+
+    fn set_of(names: &[&str]) -> HashSet<String> {
+        names.iter().map(|s| s.to_string()).collect()
+    }
+
+    #[test]
+    fn test_resolve_overlaps_union() {
+        let sets = vec![set_of(&["g1"]), set_of(&["g2"]), HashSet::new()];
+        assert_eq!(resolve_overlaps(sets, OverlapMode::Union), set_of(&["g1", "g2"]));
+    }
+
+    #[test]
+    fn test_resolve_overlaps_intersection_strict() {
+        let sets = vec![set_of(&["g1", "g2"]), set_of(&["g1"])];
+        assert_eq!(
+            resolve_overlaps(sets, OverlapMode::IntersectionStrict),
+            set_of(&["g1"])
+        );
+
+        let sets = vec![set_of(&["g1"]), HashSet::new()];
+        assert_eq!(
+            resolve_overlaps(sets, OverlapMode::IntersectionStrict),
+            HashSet::new()
+        );
+    }
+
+    #[test]
+    fn test_resolve_overlaps_intersection_nonempty() {
+        let sets = vec![set_of(&["g1", "g2"]), HashSet::new(), set_of(&["g1"])];
+        assert_eq!(
+            resolve_overlaps(sets, OverlapMode::IntersectionNonempty),
+            set_of(&["g1"])
+        );
+    }
+
+    #[test]
+    fn test_partition_round_robin() {
+        let buckets = partition_round_robin(vec![0, 1, 2, 3, 4], 2);
+        assert_eq!(buckets, vec![vec![0, 2, 4], vec![1, 3]]);
+    }
+
+    #[test]
+    fn test_merge_context() {
+        let mut ctx = Context::default();
+        ctx.counts.insert(String::from("g1"), 1.0);
+        ctx.no_feature = 2.0;
+        ctx.ambiguous = 1.0;
+
+        let mut other = Context::default();
+        other.counts.insert(String::from("g1"), 3.0);
+        other.counts.insert(String::from("g2"), 2.0);
+        other.no_feature = 1.0;
+        other.ambiguous = 0.0;
+
+        merge_context(&mut ctx, other);
+
+        assert_eq!(ctx.counts.get("g1"), Some(&4.0));
+        assert_eq!(ctx.counts.get("g2"), Some(&2.0));
+        assert_eq!(ctx.no_feature, 3.0);
+        assert_eq!(ctx.ambiguous, 1.0);
+    }
+
+    #[test]
+    fn test_weight_from_nh_defaults_to_one_without_nh_tag() {
+        assert_eq!(weight_from_nh(None), 1.0);
+    }
+
+    #[test]
+    fn test_weight_from_nh_is_fractional_for_multi_mapping_reads() {
+        assert_eq!(weight_from_nh(Some(&Value::Int32(4))), 0.25);
+        assert_eq!(weight_from_nh(Some(&Value::UInt8(2))), 0.5);
+        assert_eq!(weight_from_nh(Some(&Value::Int8(1))), 1.0);
+    }
+
+    #[test]
+    fn test_weight_from_nh_guards_against_non_positive_values() {
+        assert_eq!(weight_from_nh(Some(&Value::Int32(0))), 1.0);
+        assert_eq!(weight_from_nh(Some(&Value::Int32(-1))), 1.0);
+    }
 }
@@ -3,14 +3,19 @@ mod pair_position;
 pub use self::pair_position::PairPosition;
 
 use std::{
-    collections::{hash_map::Drain, HashMap},
+    collections::{hash_map::Drain, HashMap, VecDeque},
     convert::TryFrom,
-    io,
+    env, fs,
+    io::{self, Read, Seek, SeekFrom, Write},
+    process,
+    sync::atomic::{AtomicUsize, Ordering},
 };
 
 use log::warn;
 use noodles_bam as bam;
 
+static SPILL_ID: AtomicUsize = AtomicUsize::new(0);
+
 type RecordKey = (
     Vec<u8>,
     PairPosition,
@@ -24,7 +29,11 @@ type RecordKey = (
 pub struct RecordPairs<I> {
     records: I,
     buf: HashMap<RecordKey, bam::Record>,
+    order: VecDeque<RecordKey>,
+    max_buffered: Option<usize>,
+    spill: Option<Spill>,
     primary_only: bool,
+    warn_on_singletons: bool,
 }
 
 impl<I> RecordPairs<I>
@@ -32,13 +41,35 @@ where
     I: Iterator<Item = io::Result<bam::Record>>,
 {
     pub fn new(records: I, primary_only: bool) -> RecordPairs<I> {
+        RecordPairs::with_max_buffered(records, primary_only, None)
+    }
+
+    /// Creates a `RecordPairs` that caps its in-memory buffer of unpaired
+    /// records at `max_buffered` entries, spilling the oldest ones to disk
+    /// once full. `max_buffered` of `None` preserves the unbounded behavior.
+    pub fn with_max_buffered(
+        records: I,
+        primary_only: bool,
+        max_buffered: Option<usize>,
+    ) -> RecordPairs<I> {
         RecordPairs {
             records,
             buf: HashMap::new(),
+            order: VecDeque::new(),
+            max_buffered,
+            spill: None,
             primary_only,
+            warn_on_singletons: true,
         }
     }
 
+    /// Suppresses the "N records are singletons" warning normally logged on
+    /// stream exhaustion, for callers that still plan to reconcile leftovers
+    /// elsewhere before treating them as genuine singletons.
+    pub(crate) fn silence_singleton_warning(&mut self) {
+        self.warn_on_singletons = false;
+    }
+
     fn next_pair(&mut self) -> Option<io::Result<(bam::Record, bam::Record)>> {
         loop {
             let record = match self.records.next() {
@@ -46,13 +77,7 @@ where
                     Ok(r) => r,
                     Err(e) => return Some(Err(e)),
                 },
-                None => {
-                    if !self.buf.is_empty() {
-                        warn!("{} records are singletons", self.buf.len());
-                    }
-
-                    return None;
-                }
+                None => return self.warn_on_exhaustion(),
             };
 
             if self.primary_only && is_not_primary(&record) {
@@ -64,11 +89,14 @@ where
                 Err(e) => return Some(Err(e)),
             };
 
-            if let Some(mate) = self.buf.remove(&mate_key) {
-                return match mate_key.1 {
-                    PairPosition::First => Some(Ok((mate, record))),
-                    PairPosition::Second => Some(Ok((record, mate))),
-                };
+            if let Some(mate) = self.remove_buffered(&mate_key) {
+                return Some(Ok(order_pair(mate_key.1, mate, record)));
+            }
+
+            match self.take_spilled(&mate_key) {
+                Ok(Some(mate)) => return Some(Ok(order_pair(mate_key.1, mate, record))),
+                Ok(None) => {}
+                Err(e) => return Some(Err(e)),
             }
 
             let key = match key(&record) {
@@ -76,15 +104,90 @@ where
                 Err(e) => return Some(Err(e)),
             };
 
-            self.buf.insert(key, record.clone());
+            if let Err(e) = self.buffer(key, record) {
+                return Some(Err(e));
+            }
         }
     }
 
-    pub fn singletons(&mut self) -> Singletons {
-        Singletons {
-            drain: self.buf.drain(),
+    fn buffer(&mut self, key: RecordKey, record: bam::Record) -> io::Result<()> {
+        self.buf.insert(key.clone(), record);
+
+        if let Some(max_buffered) = self.max_buffered {
+            self.order.push_back(key);
+
+            while self.buf.len() > max_buffered {
+                self.evict_oldest()?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Removes `key` from `buf`, and from `order` too when eviction is in
+    /// play, so `order` never holds a dead entry for an already-paired key.
+    /// `order` is only populated when `max_buffered` is set, so this is a
+    /// plain `HashMap::remove` on the default, unbounded path.
+    fn remove_buffered(&mut self, key: &RecordKey) -> Option<bam::Record> {
+        let record = self.buf.remove(key)?;
+
+        if self.max_buffered.is_some() {
+            if let Some(pos) = self.order.iter().position(|k| k == key) {
+                self.order.remove(pos);
+            }
+        }
+
+        Some(record)
+    }
+
+    fn evict_oldest(&mut self) -> io::Result<()> {
+        if let Some(key) = self.order.pop_front() {
+            if let Some(record) = self.buf.remove(&key) {
+                let spill = self.spill_mut()?;
+                spill.write(key, &record)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn take_spilled(&mut self, key: &RecordKey) -> io::Result<Option<bam::Record>> {
+        match self.spill.as_mut() {
+            Some(spill) => spill.take(key),
+            None => Ok(None),
         }
     }
+
+    fn spill_mut(&mut self) -> io::Result<&mut Spill> {
+        if self.spill.is_none() {
+            self.spill = Some(Spill::create()?);
+        }
+
+        Ok(self.spill.as_mut().unwrap())
+    }
+
+    fn warn_on_exhaustion(&mut self) -> Option<io::Result<(bam::Record, bam::Record)>> {
+        let spilled = self.spill.as_ref().map(Spill::len).unwrap_or(0);
+        let total = self.buf.len() + spilled;
+
+        if self.warn_on_singletons && total > 0 {
+            warn!("{} records are singletons", total);
+        }
+
+        None
+    }
+
+    pub fn singletons(&mut self) -> io::Result<Singletons> {
+        let spilled = match self.spill.as_mut() {
+            Some(spill) => spill.drain()?,
+            None => Vec::new(),
+        };
+
+        Ok(Singletons {
+            drain: self.buf.drain(),
+            spilled: spilled.into_iter(),
+        })
+    }
 }
 
 impl<I> Iterator for RecordPairs<I>
@@ -98,6 +201,17 @@ where
     }
 }
 
+fn order_pair(
+    mate_position: PairPosition,
+    mate: bam::Record,
+    record: bam::Record,
+) -> (bam::Record, bam::Record) {
+    match mate_position {
+        PairPosition::First => (mate, record),
+        PairPosition::Second => (record, mate),
+    }
+}
+
 fn is_not_primary(record: &bam::Record) -> bool {
     let flags = record.flags();
     flags.is_secondary() || flags.is_supplementary()
@@ -133,14 +247,277 @@ fn mate_key(record: &bam::Record) -> io::Result<RecordKey> {
     ))
 }
 
+/// A temporary on-disk store for records evicted from `RecordPairs`'s
+/// bounded buffer, keyed by the same `RecordKey` used in memory. The backing
+/// file lives under `env::temp_dir()` and is removed on `Drop`.
+struct Spill {
+    file: fs::File,
+    path: std::path::PathBuf,
+    index: HashMap<RecordKey, (u64, u64)>,
+}
+
+impl Spill {
+    fn create() -> io::Result<Spill> {
+        let id = SPILL_ID.fetch_add(1, Ordering::Relaxed);
+        let path = env::temp_dir().join(format!("squab-record-pairs-{}-{}.spill", process::id(), id));
+
+        let file = fs::OpenOptions::new()
+            .create(true)
+            .read(true)
+            .write(true)
+            .truncate(true)
+            .open(&path)?;
+
+        Ok(Spill {
+            file,
+            path,
+            index: HashMap::new(),
+        })
+    }
+
+    fn len(&self) -> usize {
+        self.index.len()
+    }
+
+    fn write(&mut self, key: RecordKey, record: &bam::Record) -> io::Result<()> {
+        let bytes: &[u8] = record.as_ref();
+        let offset = self.file.seek(SeekFrom::End(0))?;
+
+        self.file.write_all(&(bytes.len() as u64).to_le_bytes())?;
+        self.file.write_all(bytes)?;
+
+        self.index.insert(key, (offset, bytes.len() as u64));
+
+        Ok(())
+    }
+
+    fn take(&mut self, key: &RecordKey) -> io::Result<Option<bam::Record>> {
+        match self.index.remove(key) {
+            Some((offset, len)) => self.read_at(offset, len).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    fn drain(&mut self) -> io::Result<Vec<bam::Record>> {
+        let entries: Vec<_> = self.index.drain().collect();
+
+        entries
+            .into_iter()
+            .map(|(_, (offset, len))| self.read_at(offset, len))
+            .collect()
+    }
+
+    fn read_at(&mut self, offset: u64, len: u64) -> io::Result<bam::Record> {
+        self.file.seek(SeekFrom::Start(offset + 8))?;
+
+        let mut buf = vec![0; len as usize];
+        self.file.read_exact(&mut buf)?;
+
+        bam::Record::try_from(buf).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+}
+
+impl Drop for Spill {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
 pub struct Singletons<'a> {
     drain: Drain<'a, RecordKey, bam::Record>,
+    spilled: std::vec::IntoIter<bam::Record>,
 }
 
 impl<'a> Iterator for Singletons<'a> {
     type Item = bam::Record;
 
     fn next(&mut self) -> Option<Self::Item> {
-        self.drain.next().map(|(_, r)| r)
+        self.drain
+            .next()
+            .map(|(_, r)| r)
+            .or_else(|| self.spilled.next())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample_key(read_name: &str) -> RecordKey {
+        (
+            read_name.as_bytes().to_vec(),
+            PairPosition::First,
+            Some(0),
+            Some(0),
+            Some(0),
+            Some(0),
+            0,
+        )
+    }
+
+    fn empty_records() -> impl Iterator<Item = io::Result<bam::Record>> {
+        std::iter::empty()
+    }
+
+    /// Hand-builds the fixed-size BAM alignment record header (no CIGAR,
+    /// sequence, or tags) well enough for `read_name`/`flags`/`position`/
+    /// `mate_*`/`template_length` to parse correctly.
+    fn raw_bam_record(
+        read_name: &str,
+        ref_id: i32,
+        pos: i32,
+        next_ref_id: i32,
+        next_pos: i32,
+        tlen: i32,
+        flag: u16,
+    ) -> Vec<u8> {
+        let mut buf = Vec::new();
+        buf.extend_from_slice(&ref_id.to_le_bytes());
+        buf.extend_from_slice(&pos.to_le_bytes());
+        buf.push((read_name.len() + 1) as u8); // l_read_name
+        buf.push(0); // mapq
+        buf.extend_from_slice(&0u16.to_le_bytes()); // bin
+        buf.extend_from_slice(&0u16.to_le_bytes()); // n_cigar_op
+        buf.extend_from_slice(&flag.to_le_bytes());
+        buf.extend_from_slice(&0u32.to_le_bytes()); // l_seq
+        buf.extend_from_slice(&next_ref_id.to_le_bytes());
+        buf.extend_from_slice(&next_pos.to_le_bytes());
+        buf.extend_from_slice(&tlen.to_le_bytes());
+        buf.extend_from_slice(read_name.as_bytes());
+        buf.push(0); // read_name null terminator
+        buf
+    }
+
+    fn build_record(
+        read_name: &str,
+        ref_id: i32,
+        pos: i32,
+        next_ref_id: i32,
+        next_pos: i32,
+        tlen: i32,
+        flag: u16,
+    ) -> bam::Record {
+        bam::Record::try_from(raw_bam_record(
+            read_name, ref_id, pos, next_ref_id, next_pos, tlen, flag,
+        ))
+        .unwrap()
+    }
+
+    #[test]
+    fn test_remove_buffered_keeps_order_in_sync() {
+        let mut pairs = RecordPairs::with_max_buffered(empty_records(), false, Some(4));
+        let key = sample_key("read-1");
+        let record = bam::Record::try_from(vec![0xa5]).unwrap();
+
+        pairs.buffer(key.clone(), record).unwrap();
+        assert_eq!(pairs.buf.len(), 1);
+        assert_eq!(pairs.order.len(), 1);
+
+        assert!(pairs.remove_buffered(&key).is_some());
+        assert_eq!(pairs.buf.len(), 0);
+        assert_eq!(pairs.order.len(), 0);
+    }
+
+    #[test]
+    fn test_order_not_populated_on_unbounded_path() {
+        let mut pairs = RecordPairs::with_max_buffered(empty_records(), false, None);
+        let key = sample_key("read-1");
+        let record = bam::Record::try_from(vec![0xa5]).unwrap();
+
+        pairs.buffer(key.clone(), record).unwrap();
+        assert_eq!(pairs.buf.len(), 1);
+        assert_eq!(pairs.order.len(), 0);
+
+        assert!(pairs.remove_buffered(&key).is_some());
+        assert_eq!(pairs.buf.len(), 0);
+    }
+
+    #[test]
+    fn test_buffer_spills_oldest_once_over_capacity() {
+        let mut pairs = RecordPairs::with_max_buffered(empty_records(), false, Some(1));
+
+        let key_a = sample_key("read-a");
+        let key_b = sample_key("read-b");
+
+        pairs
+            .buffer(key_a.clone(), bam::Record::try_from(vec![1]).unwrap())
+            .unwrap();
+        pairs
+            .buffer(key_b.clone(), bam::Record::try_from(vec![2]).unwrap())
+            .unwrap();
+
+        // Only one record fits in memory; the older one should have been
+        // spilled, and `order` should not retain a dead entry for it.
+        assert_eq!(pairs.buf.len(), 1);
+        assert!(pairs.buf.contains_key(&key_b));
+        assert!(!pairs.buf.contains_key(&key_a));
+        assert_eq!(pairs.order.len(), 1);
+
+        let spilled = pairs.spill.as_ref().map(Spill::len).unwrap_or(0);
+        assert_eq!(spilled, 1);
+
+        let restored = pairs.take_spilled(&key_a).unwrap().unwrap();
+        let restored_bytes: &[u8] = restored.as_ref();
+        assert_eq!(restored_bytes, &[1][..]);
+
+        // Spilled entry is consumed on read.
+        assert!(pairs.take_spilled(&key_a).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_spill_write_and_take_round_trip() {
+        let mut spill = Spill::create().unwrap();
+        let key = sample_key("read-1");
+        let record = bam::Record::try_from(vec![1, 2, 3, 4]).unwrap();
+
+        spill.write(key.clone(), &record).unwrap();
+        assert_eq!(spill.len(), 1);
+
+        let restored = spill.take(&key).unwrap().unwrap();
+        let restored_bytes: &[u8] = restored.as_ref();
+        assert_eq!(restored_bytes, &[1, 2, 3, 4][..]);
+        assert_eq!(spill.len(), 0);
+    }
+
+    #[test]
+    fn test_spill_file_removed_on_drop() {
+        let spill = Spill::create().unwrap();
+        let path = spill.path.clone();
+        assert!(path.exists());
+
+        drop(spill);
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn test_mates_split_across_regions_are_reunited_by_a_fresh_record_pairs() {
+        let r1 = build_record("read-1", 0, 100, 0, 200, 150, 0x41);
+        let r2 = build_record("read-1", 0, 200, 0, 100, -150, 0x81);
+
+        // Each mate is the only record in its own "region" stream, so each
+        // is a singleton within its own `RecordPairs` — the same state
+        // `count_paired_end_records_parallel` sees per-worker before
+        // cross-region reconciliation pools the leftovers back together.
+        let mut region_a = RecordPairs::new(std::iter::once(Ok(r1)), false);
+        assert!(region_a.next().is_none());
+        let mut leftovers: Vec<_> = region_a.singletons().unwrap().collect();
+
+        let mut region_b = RecordPairs::new(std::iter::once(Ok(r2)), false);
+        assert!(region_b.next().is_none());
+        leftovers.extend(region_b.singletons().unwrap().collect::<Vec<_>>());
+
+        assert_eq!(leftovers.len(), 2);
+
+        // Re-running the pooled leftovers through a fresh `RecordPairs`, as
+        // `reconcile_cross_region_singletons` does, reunites the split mates
+        // into a single pair instead of counting them twice as singletons.
+        let mut reconciled = RecordPairs::new(leftovers.into_iter().map(Ok), false);
+
+        let (mate1, mate2) = reconciled.next().unwrap().unwrap();
+        assert_eq!(mate1.read_name().unwrap().to_bytes(), b"read-1");
+        assert_eq!(mate2.read_name().unwrap().to_bytes(), b"read-1");
+
+        assert!(reconciled.next().is_none());
+        assert!(reconciled.singletons().unwrap().next().is_none());
     }
 }
@@ -1,6 +1,157 @@
+use std::convert::TryFrom;
 use std::fmt::{self, Display};
+use std::io;
 use std::str::FromStr;
 
+use noodles_bam as bam;
+use noodles_sam::header::ReferenceSequences;
+
+use crate::{
+    count::{find, get_tree, resolve_overlaps, Context, OverlapMode},
+    CigarToIntervals, Features, PairPosition, StrandSpecification,
+};
+
+/// The default number of leading records sampled by [`detect_strand_specification`].
+pub const DEFAULT_DETECTION_SAMPLE_SIZE: usize = 200_000;
+
+/// The minimum fraction of strand-consistent reads (of those overlapping
+/// exactly one feature) required before a direction is reported with
+/// confidence, rather than as ambiguous.
+pub const DEFAULT_DETECTION_THRESHOLD: f64 = 0.9;
+
+/// The result of sampling a BAM for automatic strand-specificity detection.
+///
+/// `forward_ratio` and `reverse_ratio` are the fractions of sampled reads
+/// (restricted to those overlapping exactly one feature) whose orientation
+/// agreed with that feature's strand under the `Forward` and `Reverse`
+/// library conventions, respectively.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct StrandDetection {
+    pub strand_specification: StrandSpecification,
+    pub forward_ratio: f64,
+    pub reverse_ratio: f64,
+}
+
+/// Samples up to `sample_size` records from `records`, classifying each one
+/// that overlaps exactly one feature as forward-consistent or
+/// reverse-consistent, and infers the library's [`StrandSpecification`] from
+/// the resulting ratios.
+///
+/// A direction is reported when its ratio reaches `threshold` (`>=`); when
+/// neither ratio reaches it, `StrandSpecification::None` is returned, whether
+/// the split is roughly even or merely lopsided (e.g. 0.7/0.3). The ratios are
+/// returned in every case so the caller can tell these apart and decide how to
+/// proceed (e.g. warn and fall back to an explicit `--strandedness` flag).
+pub fn detect_strand_specification<I>(
+    features: &Features,
+    reference_sequences: &ReferenceSequences,
+    records: I,
+    sample_size: usize,
+    threshold: f64,
+) -> io::Result<StrandDetection>
+where
+    I: Iterator<Item = io::Result<bam::Record>>,
+{
+    let mut ctx = Context::default();
+
+    let mut forward_consistent = 0u64;
+    let mut reverse_consistent = 0u64;
+
+    for result in records.take(sample_size) {
+        let record = result?;
+
+        let cigar = record.cigar();
+        let start = (record.position() + 1) as u64;
+        let flags = record.flags();
+
+        // Mate 2 is sequenced from the opposite strand of mate 1, so its
+        // orientation must be flipped before comparing it against either
+        // hypothesis below — the same correction `count_paired_end_record_singletons`
+        // applies. Reads with no mate (single-end) get no flip.
+        let mate_flip = match PairPosition::try_from(&record) {
+            Ok(PairPosition::First) => false,
+            Ok(PairPosition::Second) => true,
+            Err(_) => false,
+        };
+
+        let tree = match get_tree(
+            &mut ctx,
+            features,
+            reference_sequences,
+            record.reference_sequence_id(),
+            1.0,
+        )? {
+            Some(t) => t,
+            None => continue,
+        };
+
+        let forward_intervals = CigarToIntervals::new(&cigar, start, flags, mate_flip);
+        let forward_set = resolve_overlaps(
+            find(tree, forward_intervals, StrandSpecification::Forward),
+            OverlapMode::Union,
+        );
+
+        let reverse_intervals = CigarToIntervals::new(&cigar, start, flags, !mate_flip);
+        let reverse_set = resolve_overlaps(
+            find(tree, reverse_intervals, StrandSpecification::Forward),
+            OverlapMode::Union,
+        );
+
+        match classify_consistency(forward_set.len(), reverse_set.len()) {
+            Some(true) => forward_consistent += 1,
+            Some(false) => reverse_consistent += 1,
+            None => {}
+        }
+    }
+
+    let total = forward_consistent + reverse_consistent;
+
+    let (forward_ratio, reverse_ratio) = if total == 0 {
+        (0.0, 0.0)
+    } else {
+        (
+            forward_consistent as f64 / total as f64,
+            reverse_consistent as f64 / total as f64,
+        )
+    };
+
+    let strand_specification = resolve_strand_specification(forward_ratio, reverse_ratio, threshold);
+
+    Ok(StrandDetection {
+        strand_specification,
+        forward_ratio,
+        reverse_ratio,
+    })
+}
+
+/// Classifies a record as forward-consistent (`Some(true)`), reverse-consistent
+/// (`Some(false)`), or neither, from the number of features it overlaps under
+/// each orientation hypothesis. Only records overlapping exactly one feature
+/// under exactly one hypothesis are informative.
+fn classify_consistency(forward_len: usize, reverse_len: usize) -> Option<bool> {
+    match (forward_len, reverse_len) {
+        (1, 0) => Some(true),
+        (0, 1) => Some(false),
+        _ => None,
+    }
+}
+
+/// Picks a [`StrandSpecification`] from the sampled forward/reverse ratios,
+/// falling back to `None` when neither ratio clears `threshold`.
+fn resolve_strand_specification(
+    forward_ratio: f64,
+    reverse_ratio: f64,
+    threshold: f64,
+) -> StrandSpecification {
+    if forward_ratio >= threshold {
+        StrandSpecification::Forward
+    } else if reverse_ratio >= threshold {
+        StrandSpecification::Reverse
+    } else {
+        StrandSpecification::None
+    }
+}
+
 #[derive(Clone, Copy, Debug, Eq, PartialEq, Hash)]
 pub enum Strand {
     Forward,
@@ -44,7 +195,7 @@ impl FromStr for Strand {
 
 #[cfg(test)]
 mod tests {
-    use super::Strand;
+    use super::{classify_consistency, resolve_strand_specification, Strand, StrandSpecification};
 
     #[test]
     fn test_default() {
@@ -68,4 +219,34 @@ mod tests {
 
         assert!("!".parse::<Strand>().is_err());
     }
+
+    #[test]
+    fn test_classify_consistency() {
+        assert_eq!(classify_consistency(1, 0), Some(true));
+        assert_eq!(classify_consistency(0, 1), Some(false));
+        assert_eq!(classify_consistency(0, 0), None);
+        assert_eq!(classify_consistency(1, 1), None);
+    }
+
+    #[test]
+    fn test_resolve_strand_specification() {
+        assert_eq!(
+            resolve_strand_specification(0.95, 0.05, 0.9),
+            StrandSpecification::Forward
+        );
+        assert_eq!(
+            resolve_strand_specification(0.05, 0.95, 0.9),
+            StrandSpecification::Reverse
+        );
+        assert_eq!(
+            resolve_strand_specification(0.5, 0.5, 0.9),
+            StrandSpecification::None
+        );
+
+        // Threshold boundary: exactly at `threshold` counts as confident.
+        assert_eq!(
+            resolve_strand_specification(0.9, 0.0, 0.9),
+            StrandSpecification::Forward
+        );
+    }
 }